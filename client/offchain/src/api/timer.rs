@@ -24,157 +24,838 @@ use sp_core::offchain::Timestamp;
 use sp_core::offchain::PollableId;
 use sp_utils::mpsc::{tracing_unbounded, TracingUnboundedReceiver, TracingUnboundedSender};
 
-use core::cmp::{Ordering, Reverse};
 use core::future::Future;
 use core::pin::Pin;
 use core::task::{self, Poll};
 use core::time;
-use std::collections::BinaryHeap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
 
 use futures::Stream;
+use futures::stream::FusedStream;
 use futures_timer::Delay;
 
 use super::timestamp;
 
 pub use sp_core::offchain::TimerId;
 
-pub fn timer(sink: TracingUnboundedSender<PollableId>) -> (TimerApi, TimerWorker) {
-	let (to_worker, from_api) = tracing_unbounded("mpsc_ocw_timer_from");
+/// Constructs a [`TimerApi`]/[`TimerWorker`] pair driven by the real wall clock.
+pub fn timer(sink: TracingUnboundedSender<PollableId>) -> (TimerApi, TimerWorker<RealClock>) {
+	timer_with_clock(sink, RealClock)
+}
 
-	let worker = TimerWorker {
-		ready_ids: sink,
-		from_api,
-		delay: None,
-		ids: Default::default(),
-	};
+/// Like [`timer`], but driven by an arbitrary [`Clock`] — e.g. a [`MockClock`] in tests.
+pub fn timer_with_clock<C: Clock>(
+	sink: TracingUnboundedSender<PollableId>,
+	clock: C,
+) -> (TimerApi, TimerWorker<C>) {
+	let (api, schedule) = new_schedule(clock);
+
+	(api, TimerWorker { schedule, ready_ids: sink })
+}
+
+/// Constructs a [`TimerApi`]/[`TimerStream`] pair driven by the real wall clock. Unlike
+/// [`timer`], elapsed IDs are read directly from the returned `Stream` rather than pushed into
+/// a caller-supplied sink, so the timer can be composed with `select!` and stream combinators.
+pub fn timer_stream() -> (TimerApi, TimerStream<RealClock>) {
+	timer_stream_with_clock(RealClock)
+}
+
+/// Like [`timer_stream`], but driven by an arbitrary [`Clock`].
+pub fn timer_stream_with_clock<C: Clock>(clock: C) -> (TimerApi, TimerStream<C>) {
+	let (api, schedule) = new_schedule(clock);
+
+	(api, TimerStream { schedule })
+}
+
+fn new_schedule<C: Clock>(clock: C) -> (TimerApi, TimerSchedule<C>) {
+	let (to_worker, from_api) = tracing_unbounded("mpsc_ocw_timer_from");
 
 	let api = TimerApi {
 		to_worker,
 		next_id: TimerId(0),
 	};
 
-	(api, worker)
+	(api, TimerSchedule::new(from_api, clock))
+}
+
+/// Abstracts over the passage of time, so [`TimerWorker`] can be driven by simulated time in
+/// tests instead of real sleeps.
+pub trait Clock: Unpin {
+	/// The future returned by [`Clock::delay_until`].
+	type Delay: Future<Output = ()> + Unpin;
+
+	/// The current time, according to this clock.
+	fn now(&self) -> Timestamp;
+
+	/// A future that resolves once this clock's `now()` reaches `at`.
+	fn delay_until(&self, at: Timestamp) -> Self::Delay;
+}
+
+/// The real wall clock, backed by [`futures_timer::Delay`].
+#[derive(Clone, Copy, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+	type Delay = Delay;
+
+	fn now(&self) -> Timestamp {
+		timestamp::now()
+	}
+
+	fn delay_until(&self, at: Timestamp) -> Delay {
+		let diff = timestamp::timestamp_from_now(at);
+		Delay::new(time::Duration::from_millis(diff.as_millis() as u64))
+	}
+}
+
+/// A simulated clock for deterministic tests, modeled on tor-rtmock's
+/// `SimpleMockTimeProvider`.
+///
+/// Time only moves when a test calls [`MockClock::advance`] or [`MockClock::jump`]; any
+/// [`MockClockDelay`] whose deadline has since passed is woken at that point. This lets tests
+/// assert the exact firing order of a batch of timers by stepping time deterministically,
+/// without relying on real sleeps and the flakiness that brings under CI load.
+#[derive(Clone)]
+pub struct MockClock(Arc<Mutex<MockClockState>>);
+
+struct MockClockState {
+	now: Timestamp,
+	/// Deadlines waiting to be woken, alongside the waker to notify once `now` reaches them.
+	waiters: Vec<(Timestamp, task::Waker)>,
+}
+
+impl MockClock {
+	/// Creates a clock whose `now()` starts at `start`.
+	pub fn new(start: Timestamp) -> Self {
+		MockClock(Arc::new(Mutex::new(MockClockState { now: start, waiters: Vec::new() })))
+	}
+
+	/// Advances the clock by `duration`, waking any [`MockClockDelay`] whose deadline has now
+	/// passed.
+	pub fn advance(&self, duration: offchain::Duration) {
+		let mut state = self.0.lock().expect("MockClock mutex poisoned");
+		state.now = state.now.add(duration);
+		Self::wake_elapsed(&mut state);
+	}
+
+	/// Jumps the clock directly to `at`, simulating a clock warp, waking anything now due.
+	pub fn jump(&self, at: Timestamp) {
+		let mut state = self.0.lock().expect("MockClock mutex poisoned");
+		state.now = at;
+		Self::wake_elapsed(&mut state);
+	}
+
+	fn wake_elapsed(state: &mut MockClockState) {
+		let now = state.now;
+		state.waiters.retain(|(at, waker)| {
+			if *at <= now {
+				waker.wake_by_ref();
+				false
+			} else {
+				true
+			}
+		});
+	}
+}
+
+impl Clock for MockClock {
+	type Delay = MockClockDelay;
+
+	fn now(&self) -> Timestamp {
+		self.0.lock().expect("MockClock mutex poisoned").now
+	}
+
+	fn delay_until(&self, at: Timestamp) -> MockClockDelay {
+		MockClockDelay { state: self.0.clone(), at }
+	}
+}
+
+/// The [`Future`] returned by [`MockClock::delay_until`].
+pub struct MockClockDelay {
+	state: Arc<Mutex<MockClockState>>,
+	at: Timestamp,
+}
+
+impl Future for MockClockDelay {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<()> {
+		let mut state = self.state.lock().expect("MockClock mutex poisoned");
+		if self.at <= state.now {
+			return Poll::Ready(());
+		}
+		state.waiters.push((self.at, cx.waker().clone()));
+		Poll::Pending
+	}
+}
+
+/// Whether a timer fires once or re-arms itself on a fixed period.
+#[derive(Clone, Copy)]
+enum TimerType {
+	/// Fires once, then is forgotten.
+	Once,
+	/// Fires repeatedly, every `period`.
+	Repeat(offchain::Duration),
+}
+
+/// A command sent from the [`TimerApi`] to the [`TimerWorker`].
+enum TimerCmd {
+	/// Arm a new timer identified by `id`, due to fire `delay` from whenever the worker's clock
+	/// observes this command.
+	///
+	/// The delay is relative rather than an absolute [`Timestamp`] because [`TimerApi`] isn't
+	/// generic over [`Clock`] and so has no way to convert it to one itself; the worker resolves
+	/// it against its own clock once the command is processed.
+	Start { id: TimerId, delay: offchain::Duration, kind: TimerType },
+	/// Cancel a previously started timer. A no-op if the timer already fired.
+	Cancel(TimerId),
 }
 
 pub struct TimerApi {
-	/// Used to enqueue new timer in the `TimerWorker`.
-	to_worker: TracingUnboundedSender<(TimerId, Timestamp)>,
+	/// Used to enqueue new timer commands in the `TimerWorker`.
+	to_worker: TracingUnboundedSender<TimerCmd>,
 	/// Counter used to generate new timer IDs.
 	next_id: TimerId,
 }
 
 impl TimerApi {
-	/// Starts a new timer that resolves a `duration` from the current epoch.
+	/// Starts a new timer that resolves `duration` from whenever the worker observes it.
 	pub fn start_timer(&mut self, duration: offchain::Duration) -> TimerId {
+		self.enqueue(duration, TimerType::Once)
+	}
+
+	/// Starts a new recurring timer that fires every `period`, starting `period` from whenever
+	/// the worker observes it.
+	///
+	/// Each re-arm is anchored to the previous deadline rather than to the firing time, so the
+	/// interval doesn't drift under scheduling jitter.
+	pub fn start_interval(&mut self, period: offchain::Duration) -> TimerId {
+		self.enqueue(period, TimerType::Repeat(period))
+	}
+
+	/// Cancels a pending timer, preventing it from ever firing (or firing again, for intervals).
+	///
+	/// Cancelling a timer that already fired (or doesn't exist) is a no-op.
+	pub fn cancel_timer(&mut self, id: TimerId) {
+		self.to_worker.unbounded_send(TimerCmd::Cancel(id))
+			.expect("TimerWorker should live and be driven as long as TimerApi is alive; qed")
+	}
+
+	fn enqueue(&mut self, delay: offchain::Duration, kind: TimerType) -> TimerId {
 		let id = self.next_id;
 		self.next_id = TimerId(self.next_id.0 + 1);
 
-		let timestamp = timestamp::now().add(duration);
-
-		self.to_worker.unbounded_send((id, timestamp))
+		self.to_worker.unbounded_send(TimerCmd::Start { id, delay, kind })
 			.map(|_| id)
 			.expect("TimerWorker should live and be driven as long as TimerApi is alive; qed")
 	}
 }
 
-/// A `TimerId` wrapper that implements `Ord` and `Eq` using an additional
-/// `Timestamp` value.
-struct TimerIdWithTimestamp {
-	key: Timestamp,
+/// Number of hierarchical levels in [`TimingWheel`]. Level 0 has the finest granularity (1ms
+/// per slot); each subsequent level's slots span `WHEEL_SLOTS` times the level below it.
+const WHEEL_LEVELS: usize = 6;
+/// Slots per level, chosen so a slot index is exactly `WHEEL_SLOT_BITS` wide.
+const WHEEL_SLOTS: usize = 64;
+const WHEEL_SLOT_BITS: u32 = 6;
+const WHEEL_SLOT_MASK: u64 = (WHEEL_SLOTS as u64) - 1;
+
+/// A single pending timer, bucketed somewhere in the [`TimingWheel`].
+struct WheelEntry {
 	id: TimerId,
+	/// Absolute deadline, in milliseconds since the wheel's `base`.
+	deadline_tick: u64,
+	/// Monotonically increasing index assigned each time the timer is armed, used to break ties
+	/// between entries sharing a `deadline_tick`. Preserved across cascades, so it reflects the
+	/// arming order rather than the order a batch happened to be visited. An interval is given a
+	/// fresh index on every re-arm, so it ranks by when it was last re-armed, not by when it was
+	/// first started.
+	seq: u64,
 }
 
-impl PartialEq for TimerIdWithTimestamp {
-	fn eq(&self, other: &Self) -> bool {
-		PartialEq::eq(&self.key, &other.key)
-	}
+/// A hierarchical hashed timing wheel, as popularised by tokio-timer, used in place of a
+/// `BinaryHeap` to keep the cost of inserting and expiring a timer independent of the number of
+/// pending timers (finding the earliest pending timer, see [`TimingWheel::earliest`], is not).
+///
+/// Timers are bucketed by `(level, slot)`, where `level` is picked from the magnitude of the
+/// delay until they're due and `slot` from that level's 6-bit field of their absolute deadline
+/// (the low bits at level 0). Advancing
+/// the wheel only visits the slots whose time range has elapsed since the last advance
+/// ("cascading" coarse-level entries down into finer levels as their deadline approaches),
+/// rather than walking every elapsed millisecond.
+struct TimingWheel {
+	/// The point in time that tick `0` corresponds to.
+	base: Timestamp,
+	/// `levels[l][s]` holds the timers bucketed at level `l`, slot `s`, in insertion order.
+	levels: [[VecDeque<WheelEntry>; WHEEL_SLOTS]; WHEEL_LEVELS],
+	/// Bitmask per level; bit `s` is set iff `levels[l][s]` is non-empty.
+	occupied: [u64; WHEEL_LEVELS],
+	/// Tick up to (and including) which the wheel has already been advanced.
+	current_tick: u64,
+	/// Counter handed out as [`WheelEntry::seq`] for each freshly-armed timer.
+	next_seq: u64,
+	/// Entries armed (or re-armed) with a deadline already at or before `current_tick`.
+	///
+	/// Bucketing is keyed off the low bits of the deadline tick, so an entry whose deadline has
+	/// already passed by the time it's inserted would otherwise sit in whatever slot its
+	/// deadline happens to hash to and only be visited again once the wheel's tick index wraps
+	/// all the way back around to that slot (tens of milliseconds later at level 0) — firing
+	/// late instead of on the very next `advance`. Staging them here instead means `advance`
+	/// always drains them immediately, regardless of which bucket they'd have hashed to.
+	due_now: Vec<WheelEntry>,
 }
 
-impl Eq for TimerIdWithTimestamp {}
+impl TimingWheel {
+	fn new(base: Timestamp) -> Self {
+		TimingWheel {
+			base,
+			levels: [(); WHEEL_LEVELS].map(|_| [(); WHEEL_SLOTS].map(|_| VecDeque::new())),
+			occupied: [0; WHEEL_LEVELS],
+			current_tick: 0,
+			next_seq: 0,
+			due_now: Vec::new(),
+		}
+	}
+
+	fn tick_of(&self, at: Timestamp) -> u64 {
+		at.diff(&self.base).millis()
+	}
 
-impl PartialOrd for TimerIdWithTimestamp {
-	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-		PartialOrd::partial_cmp(&self.key, &other.key)
+	fn timestamp_of(&self, tick: u64) -> Timestamp {
+		self.base.add(offchain::Duration::from_millis(tick))
 	}
-}
 
-impl Ord for TimerIdWithTimestamp {
-	fn cmp(&self, other: &Self) -> Ordering {
-		Ord::cmp(&self.key, &other.key)
+	fn level_for_delta(delta: u64) -> usize {
+		if delta == 0 {
+			return 0;
+		}
+		let highest_bit = 63 - delta.leading_zeros();
+		((highest_bit / WHEEL_SLOT_BITS) as usize).min(WHEEL_LEVELS - 1)
+	}
+
+	fn slot_span(level: usize) -> u64 {
+		1u64 << (WHEEL_SLOT_BITS as usize * level)
+	}
+
+	fn slot_index(tick: u64, level: usize) -> usize {
+		((tick >> (WHEEL_SLOT_BITS as usize * level)) & WHEEL_SLOT_MASK) as usize
+	}
+
+	/// Buckets `entry`, relative to `reference_tick`. Used both for fresh inserts and for
+	/// cascading an entry down without disturbing its original `seq`.
+	fn insert_entry(&mut self, entry: WheelEntry, reference_tick: u64) {
+		let delta = entry.deadline_tick.saturating_sub(reference_tick);
+		let level = Self::level_for_delta(delta);
+		let slot = Self::slot_index(entry.deadline_tick, level);
+
+		self.occupied[level] |= 1 << slot;
+		self.levels[level][slot].push_back(entry);
+	}
+
+	/// Arms a new timer due at `at`, assigning it the next insertion sequence number.
+	///
+	/// If `at` is already due — on or before the tick the wheel has been advanced to — the
+	/// entry is staged in [`Self::due_now`] instead of being bucketed, so it fires on the very
+	/// next `advance` rather than being skipped until the wheel wraps back around to its slot.
+	fn insert(&mut self, id: TimerId, at: Timestamp) {
+		let deadline_tick = self.tick_of(at);
+		let seq = self.next_seq;
+		self.next_seq += 1;
+		let entry = WheelEntry { id, deadline_tick, seq };
+
+		if deadline_tick <= self.current_tick {
+			self.due_now.push(entry);
+		} else {
+			self.insert_entry(entry, self.current_tick);
+		}
+	}
+
+	/// The timestamp of the earliest pending timer that's still in `live`, if any.
+	///
+	/// Cancelled entries are left in place — the wheel has no targeted removal — but are
+	/// skipped here so a cancelled timer doesn't keep the worker waking up for it.
+	///
+	/// Scans every entry in each occupied slot and in [`Self::due_now`], cancelled ones included,
+	/// so its cost grows with the number of timers still held by the wheel.
+	fn earliest(&self, live: &HashMap<TimerId, TimerType>) -> Option<Timestamp> {
+		let mut earliest_tick = self.due_now.iter()
+			.filter(|entry| live.contains_key(&entry.id))
+			.map(|entry| entry.deadline_tick)
+			.min();
+
+		for level in 0..WHEEL_LEVELS {
+			let mut bits = self.occupied[level];
+			while bits != 0 {
+				let slot = bits.trailing_zeros() as usize;
+				bits &= bits - 1;
+
+				if let Some(front) = self.levels[level][slot].iter()
+					.filter(|entry| live.contains_key(&entry.id))
+					.map(|entry| entry.deadline_tick)
+					.min()
+				{
+					earliest_tick = Some(earliest_tick.map_or(front, |e: u64| e.min(front)));
+				}
+			}
+		}
+		earliest_tick.map(|tick| self.timestamp_of(tick))
+	}
+
+	/// Advances the wheel to `at`, returning every timer that is now due, in `(id, deadline)`
+	/// form, ordered by deadline and then by [`WheelEntry::seq`], the order they were armed in (so
+	/// timers sharing — or rounding to — the same deadline fire in a stable, reproducible order,
+	/// regardless of which slots the implementation happened to visit them in). A re-armed
+	/// interval ranks by its latest re-arm, not by when it was first started.
+	///
+	/// Only the slots whose range falls within `(previous tick, new tick]` are visited: higher
+	/// levels are cascaded down into finer ones as their coarse slot is crossed, and a level is
+	/// skipped entirely if its slot index hasn't changed, bounding the work to the slots crossed
+	/// since the last call rather than to the milliseconds elapsed. [`Self::due_now`] is drained
+	/// unconditionally, even if `at` hasn't advanced the tick at all, since entries can land
+	/// there between calls without the wheel's own clock moving.
+	fn advance(&mut self, at: Timestamp) -> Vec<(TimerId, Timestamp)> {
+		let mut fired: Vec<WheelEntry> = core::mem::take(&mut self.due_now);
+
+		let target_tick = self.tick_of(at);
+		if target_tick > self.current_tick {
+			let previous_tick = self.current_tick;
+
+			for level in 0..WHEEL_LEVELS {
+				let span = Self::slot_span(level);
+				let old_idx = previous_tick / span;
+				let new_idx = target_tick / span;
+				if old_idx == new_idx {
+					continue;
+				}
+
+				// Only `WHEEL_SLOTS` distinct buckets exist at this level; once that many
+				// boundaries have been crossed, every bucket has already been visited once.
+				let crossed = (new_idx - old_idx).min(WHEEL_SLOTS as u64);
+				for step in 1..=crossed {
+					let slot = ((old_idx + step) % WHEEL_SLOTS as u64) as usize;
+					let entries = core::mem::take(&mut self.levels[level][slot]);
+					self.occupied[level] &= !(1 << slot);
+
+					for entry in entries {
+						if entry.deadline_tick <= target_tick {
+							fired.push(entry);
+						} else {
+							// Not due yet: cascade it into the level appropriate for its
+							// remaining delta from the new current tick, preserving its `seq`.
+							self.insert_entry(entry, target_tick);
+						}
+					}
+				}
+			}
+
+			self.current_tick = target_tick;
+		}
+
+		// Process the whole equal-deadline batch in a single pass, in deterministic order.
+		fired.sort_by_key(|entry| (entry.deadline_tick, entry.seq));
+		fired.into_iter().map(|entry| (entry.id, self.timestamp_of(entry.deadline_tick))).collect()
 	}
 }
 
-pub struct TimerWorker {
-	/// Used to broadcast elapsed timers' IDs.
-	ready_ids: TracingUnboundedSender<PollableId>,
+/// The scheduling state shared by [`TimerWorker`] and [`TimerStream`]; the two only differ in
+/// how they hand fired IDs back to the caller.
+struct TimerSchedule<C: Clock> {
 	/// Used to receive messages from the `TimerApi`.
-	from_api: TracingUnboundedReceiver<(TimerId, Timestamp)>,
+	from_api: TracingUnboundedReceiver<TimerCmd>,
 	/// Timer future driving the wakeups for worker future.
-	delay: Option<(Timestamp, Delay)>,
-	/// Priority queue for timers, yielding those with earliest timestamps.
-	ids: BinaryHeap<Reverse<TimerIdWithTimestamp>>,
+	delay: Option<(Timestamp, C::Delay)>,
+	/// Scheduler holding all pending timers.
+	wheel: TimingWheel,
+	/// Timers that are still live, i.e. haven't fired (for one-shots) or been cancelled, mapped
+	/// to their [`TimerType`].
+	///
+	/// The wheel has no efficient removal, so cancellation is handled lazily: a cancelled ID is
+	/// simply removed from this map, and entries the wheel reports as due whose ID is no longer
+	/// live are dropped instead of being forwarded to the caller.
+	live: HashMap<TimerId, TimerType>,
+	/// IDs that fired and are waiting to be handed off.
+	pending: VecDeque<TimerId>,
+	/// Set once `from_api` has closed, i.e. the corresponding [`TimerApi`] was dropped.
+	api_closed: bool,
+	/// Source of truth for "now", real or simulated.
+	clock: C,
 }
 
-impl Future for TimerWorker {
-	type Output = ();
+impl<C: Clock> TimerSchedule<C> {
+	fn new(from_api: TracingUnboundedReceiver<TimerCmd>, clock: C) -> Self {
+		TimerSchedule {
+			wheel: TimingWheel::new(clock.now()),
+			from_api,
+			delay: None,
+			live: Default::default(),
+			pending: Default::default(),
+			api_closed: false,
+			clock,
+		}
+	}
 
-	fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Self::Output> {
-		let this = &mut *self;
+	/// Recomputes `self.delay` from the wheel's earliest pending timer, dropping it if none
+	/// remain.
+	///
+	/// If that timer is already due, no `Delay` is armed for it at all: a zero (or negative)
+	/// length delay wouldn't be wrong, but re-arming it every `poll_progress` call without ever
+	/// making progress — e.g. under a [`MockClock`] that isn't being advanced — would spin
+	/// forever instead of just waiting to be polled again. Wake the task directly instead, so
+	/// the next `poll_progress` call drains it via `advance`.
+	fn rearm_delay(&mut self, cx: &mut task::Context) {
+		match self.wheel.earliest(&self.live) {
+			Some(timestamp) if timestamp <= self.clock.now() => {
+				self.delay = None;
+				cx.waker().wake_by_ref();
+			},
+			Some(timestamp) => {
+				self.delay = Some((timestamp, self.clock.delay_until(timestamp)));
+				cx.waker().wake_by_ref();
+			},
+			None => {
+				self.delay = None;
+			},
+		}
+	}
 
+	/// Advances the wheel and drains `from_api` by one step, queuing any newly-fired IDs onto
+	/// `pending`.
+	///
+	/// Returns `Poll::Ready(())` once the `TimerApi` has been dropped and every timer it armed
+	/// has either fired or been cancelled — at which point nothing can ever become due again —
+	/// and `Poll::Pending` otherwise.
+	fn poll_progress(&mut self, cx: &mut task::Context) -> Poll<()> {
 		// Poll the underlying future to register for a possible future wakeup
-		if let Some((_, delay)) = &mut this.delay {
+		if let Some((_, delay)) = &mut self.delay {
 			match Future::poll(Pin::new(delay), cx) {
-				Poll::Ready(..) => { this.delay.take(); },
+				Poll::Ready(..) => { self.delay.take(); },
 				Poll::Pending => {},
 			}
 		}
 
-		// Process elapsed timers
-		let now = timestamp::now();
-		while let Some(true) = this.ids.peek().map(|x| x.0.key <= now) {
-			let id = this.ids.pop().expect("We just peeked an element; qed").0.id;
-
-			let _ = this.ready_ids.unbounded_send(id.into());
+		// Advance the wheel to now, processing (and re-arming, for intervals) anything due,
+		// skipping any that were cancelled in the meantime.
+		let now = self.clock.now();
+		for (id, fired_at) in self.wheel.advance(now) {
+			match self.live.get(&id) {
+				Some(TimerType::Once) => {
+					self.live.remove(&id);
+					self.pending.push_back(id);
+				},
+				Some(&TimerType::Repeat(period)) => {
+					// Re-arm anchored to the deadline that just fired, not `now`, so the
+					// interval doesn't accumulate drift.
+					self.wheel.insert(id, fired_at.add(period));
+					self.pending.push_back(id);
+				},
+				// Cancelled in the meantime; drop silently.
+				None => {},
+			}
 		}
 
 		// Register the task for a wake-up when we can progress with the earliest timer
-		match (this.ids.peek(), this.delay.as_ref()) {
-			(Some(Reverse(TimerIdWithTimestamp { key: timestamp, .. })), None) => {
-				let diff = timestamp::timestamp_from_now(*timestamp);
-				let duration = time::Duration::from_millis(diff.as_millis() as u64);
+		if self.delay.is_none() {
+			self.rearm_delay(cx);
+		}
 
-				this.delay = Some((*timestamp, Delay::new(duration)));
-				// Reschedule the task to poll the new underlying timer future
-				cx.waker().wake_by_ref();
-			},
-			_ => {},
+		// Check for messages coming from the [`TimerApi`], unless it has already closed.
+		if !self.api_closed {
+			match Stream::poll_next(Pin::new(&mut self.from_api), cx) {
+				Poll::Pending => {},
+				Poll::Ready(Some(TimerCmd::Start { id, delay, kind })) => {
+					let timestamp = self.clock.now().add(delay);
+					self.wheel.insert(id, timestamp);
+					self.live.insert(id, kind);
+
+					// Newly added timer may resolve before currently registered
+					// earliest one - if that's the case, adjust the new delay.
+					match self.delay.as_ref() {
+						Some((earliest, _)) if earliest.diff(&timestamp).millis() > 0 => {
+							self.delay.take();
+							self.rearm_delay(cx);
+						},
+						None => self.rearm_delay(cx),
+						_ => {},
+					}
+					// Reschedule the task to poll the new underlying timer future
+					// (delay could've changed or a fresh, single timer could've been added)
+					cx.waker().wake_by_ref();
+				},
+				Poll::Ready(Some(TimerCmd::Cancel(id))) => {
+					self.live.remove(&id);
+
+					// The cancelled timer may have been driving `self.delay`; recompute it
+					// against the new earliest (or drop it entirely) so we don't wake uselessly.
+					self.delay.take();
+					self.rearm_delay(cx);
+
+					// Reschedule the task to poll the new underlying timer future
+					// (delay could've moved to a later timer or been dropped entirely)
+					cx.waker().wake_by_ref();
+				},
+				Poll::Ready(None) => {
+					self.api_closed = true;
+				},
+			}
+		}
+
+		if self.api_closed && self.live.is_empty() {
+			Poll::Ready(())
+		} else {
+			Poll::Pending
 		}
+	}
+}
+
+/// Drives pending [`TimerApi`] timers, pushing each elapsed ID into a caller-supplied sink.
+///
+/// See [`TimerStream`] for an alternative that exposes fired IDs as a `Stream` instead.
+pub struct TimerWorker<C: Clock = RealClock> {
+	schedule: TimerSchedule<C>,
+	/// Used to broadcast elapsed timers' IDs.
+	ready_ids: TracingUnboundedSender<PollableId>,
+}
 
-		// Check for messages coming from the [`TimerApi`].
-		match Stream::poll_next(Pin::new(&mut this.from_api), cx) {
+impl<C: Clock> Future for TimerWorker<C> {
+	type Output = ();
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Self::Output> {
+		let this = &mut *self;
+
+		let result = this.schedule.poll_progress(cx);
+		while let Some(id) = this.schedule.pending.pop_front() {
+			let _ = this.ready_ids.unbounded_send(id.into());
+		}
+		result
+	}
+}
+
+/// Drives pending [`TimerApi`] timers, exposing each elapsed ID as a `Stream` item instead of
+/// pushing it into a sink, so the worker can be composed directly with `select!` and stream
+/// combinators.
+pub struct TimerStream<C: Clock = RealClock> {
+	schedule: TimerSchedule<C>,
+}
+
+impl<C: Clock> Stream for TimerStream<C> {
+	type Item = TimerId;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<TimerId>> {
+		let this = &mut *self;
+
+		if let Some(id) = this.schedule.pending.pop_front() {
+			return Poll::Ready(Some(id));
+		}
+		let result = this.schedule.poll_progress(cx);
+		if let Some(id) = this.schedule.pending.pop_front() {
+			return Poll::Ready(Some(id));
+		}
+		match result {
+			// `poll_progress` only returns `Ready` once nothing can ever fire again, so by now
+			// `pending` is drained for good and the stream is over.
+			Poll::Ready(()) => Poll::Ready(None),
 			Poll::Pending => Poll::Pending,
-			Poll::Ready(Some((id, timestamp))) => {
-				this.ids.push(Reverse(TimerIdWithTimestamp { key: timestamp, id }));
-
-				// Newly added timer may resolve before currently registered
-				// earliest one - if that's the case, adjust the new delay.
-				match this.delay.as_mut() {
-					Some((earliest, delay)) if earliest.diff(&timestamp).millis() > 0 => {
-						let diff = timestamp::timestamp_from_now(timestamp);
-						let duration = time::Duration::from_millis(diff.as_millis() as u64);
-
-						delay.reset(duration);
-					},
-					_ => {},
-				}
-				// Reschedule the task to poll the new underlying timer future
-				// (delay could've changed or a fresh, single timer could've been added)
-				cx.waker().wake_by_ref();
+		}
+	}
+}
 
-				Poll::Pending
-			},
-			// Finished, stop the worker
-			Poll::Ready(None) => Poll::Ready(()),
+impl<C: Clock> FusedStream for TimerStream<C> {
+	fn is_terminated(&self) -> bool {
+		self.schedule.api_closed && self.schedule.live.is_empty() && self.schedule.pending.is_empty()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::task::{noop_waker, waker, ArcWake};
+
+	/// A waker that just records whether it was ever invoked.
+	struct FlagWaker(Mutex<bool>);
+
+	impl ArcWake for FlagWaker {
+		fn wake_by_ref(arc_self: &Arc<Self>) {
+			*arc_self.0.lock().expect("FlagWaker mutex poisoned") = true;
 		}
 	}
-}
\ No newline at end of file
+
+	/// Polls `stream` enough times to flush every currently-queued `TimerApi` command and
+	/// wheel advance through to a fixed point, collecting whatever fires along the way.
+	///
+	/// `poll_progress` only drains one queued command (or one wheel advance) per call and
+	/// relies on the waker to get re-polled; a no-op waker never actually reschedules us, so we
+	/// poll a generous, fixed number of times by hand instead of stopping at the first `Pending`.
+	fn drain(stream: &mut TimerStream<MockClock>) -> Vec<TimerId> {
+		let waker = noop_waker();
+		let mut cx = task::Context::from_waker(&waker);
+		let mut fired = Vec::new();
+		for _ in 0..64 {
+			match Stream::poll_next(Pin::new(stream), &mut cx) {
+				Poll::Ready(Some(id)) => fired.push(id),
+				Poll::Ready(None) => break,
+				Poll::Pending => {},
+			}
+		}
+		fired
+	}
+
+	#[test]
+	fn batch_sharing_a_deadline_fires_in_insertion_order() {
+		let clock = MockClock::new(Timestamp(0));
+		let (mut api, mut stream) = timer_stream_with_clock(clock.clone());
+
+		let first = api.start_timer(offchain::Duration::from_millis(100));
+		let second = api.start_timer(offchain::Duration::from_millis(100));
+		let third = api.start_timer(offchain::Duration::from_millis(100));
+
+		// Flush the arming commands before advancing, so all three land on the same deadline.
+		drain(&mut stream);
+		clock.advance(offchain::Duration::from_millis(100));
+
+		assert_eq!(drain(&mut stream), vec![first, second, third]);
+	}
+
+	#[test]
+	fn cancelled_timer_never_fires() {
+		let clock = MockClock::new(Timestamp(0));
+		let (mut api, mut stream) = timer_stream_with_clock(clock.clone());
+
+		let kept = api.start_timer(offchain::Duration::from_millis(50));
+		let cancelled = api.start_timer(offchain::Duration::from_millis(50));
+		drain(&mut stream);
+
+		api.cancel_timer(cancelled);
+		drain(&mut stream);
+
+		clock.advance(offchain::Duration::from_millis(50));
+		assert_eq!(drain(&mut stream), vec![kept]);
+	}
+
+	#[test]
+	fn cancelling_head_of_queue_does_not_rearm_to_its_stale_deadline() {
+		let clock = MockClock::new(Timestamp(0));
+		let (mut api, mut stream) = timer_stream_with_clock(clock.clone());
+
+		let head = api.start_timer(offchain::Duration::from_millis(10));
+		let _later = api.start_timer(offchain::Duration::from_millis(50));
+		drain(&mut stream);
+
+		api.cancel_timer(head);
+		drain(&mut stream);
+
+		let flag = Arc::new(FlagWaker(Mutex::new(false)));
+		let raw_waker = waker(flag.clone());
+		let mut cx = task::Context::from_waker(&raw_waker);
+		assert_eq!(Stream::poll_next(Pin::new(&mut stream), &mut cx), Poll::Pending);
+
+		// Advancing to the cancelled timer's original deadline must not wake us: `self.delay`
+		// should have been re-armed against the next *live* timer (at 50ms), not the stale
+		// cancelled one (at 10ms), now that the wheel has no entry it can still report as
+		// earliest for a dead ID.
+		clock.advance(offchain::Duration::from_millis(10));
+		assert!(
+			!*flag.0.lock().unwrap(),
+			"cancelling the head timer shouldn't leave us waking for its old deadline",
+		);
+	}
+
+	#[test]
+	fn interval_rearms_against_its_own_deadline_without_drift() {
+		let clock = MockClock::new(Timestamp(0));
+		let (mut api, mut stream) = timer_stream_with_clock(clock.clone());
+
+		let id = api.start_interval(offchain::Duration::from_millis(10));
+		drain(&mut stream);
+
+		let mut fire_count = 0;
+		for _ in 0..5 {
+			clock.advance(offchain::Duration::from_millis(10));
+			fire_count += drain(&mut stream).iter().filter(|fired| **fired == id).count();
+		}
+
+		// Anchoring each re-arm to the deadline that just fired (rather than to "now") means
+		// advancing by exactly the period, five times, fires exactly five times — no drift
+		// accumulated and none skipped.
+		assert_eq!(fire_count, 5);
+	}
+
+	#[test]
+	fn interval_catches_up_after_the_clock_overshoots_multiple_periods() {
+		let clock = MockClock::new(Timestamp(0));
+		let (mut api, mut stream) = timer_stream_with_clock(clock.clone());
+
+		let id = api.start_interval(offchain::Duration::from_millis(10));
+		drain(&mut stream);
+
+		// Jump past two whole periods in one go, the way a busy worker or a test stepping in
+		// coarse increments would, instead of advancing one period at a time.
+		clock.advance(offchain::Duration::from_millis(25));
+		let fired = drain(&mut stream);
+		assert_eq!(fired, vec![id, id], "both elapsed periods (10ms and 20ms) should fire");
+
+		// The interval must keep re-arming correctly afterwards rather than getting stuck once
+		// it's caught up.
+		clock.advance(offchain::Duration::from_millis(5));
+		assert_eq!(drain(&mut stream), vec![id]);
+	}
+
+	#[test]
+	fn stream_yields_fired_timers_queued_before_api_dropped_then_ends() {
+		let clock = MockClock::new(Timestamp(0));
+		let (mut api, mut stream) = timer_stream_with_clock(clock.clone());
+
+		let first = api.start_timer(offchain::Duration::from_millis(10));
+		let second = api.start_timer(offchain::Duration::from_millis(10));
+		drain(&mut stream);
+
+		clock.advance(offchain::Duration::from_millis(10));
+		drop(api);
+
+		let mut fired = drain(&mut stream);
+		fired.sort_by_key(|id| id.0);
+		let mut expected = vec![first, second];
+		expected.sort_by_key(|id| id.0);
+		assert_eq!(fired, expected);
+
+		let waker = noop_waker();
+		let mut cx = task::Context::from_waker(&waker);
+		assert_eq!(Stream::poll_next(Pin::new(&mut stream), &mut cx), Poll::Ready(None));
+		assert!(FusedStream::is_terminated(&stream));
+	}
+
+	#[test]
+	fn timer_armed_with_an_already_due_deadline_fires_without_advancing_the_clock() {
+		let clock = MockClock::new(Timestamp(0));
+		let (mut api, mut stream) = timer_stream_with_clock(clock);
+
+		// A zero-length delay resolves to right now, so the entry is already due the instant
+		// it's armed.
+		let id = api.start_timer(offchain::Duration::from_millis(0));
+
+		// No `clock.advance` at all: if this entry were bucketed instead of staged for
+		// immediate firing, it would only be swept once the wheel's tick index wrapped all the
+		// way back around to its slot — and since the clock here never moves, it would never
+		// fire, leaving `drain` to come back empty after its fixed number of polls.
+		assert_eq!(drain(&mut stream), vec![id]);
+	}
+
+	#[test]
+	fn mock_clock_delay_resolves_once_advanced_past_its_deadline() {
+		let clock = MockClock::new(Timestamp(0));
+		let mut delay = clock.delay_until(Timestamp(100));
+
+		let flag = Arc::new(FlagWaker(Mutex::new(false)));
+		let raw_waker = waker(flag.clone());
+		let mut cx = task::Context::from_waker(&raw_waker);
+
+		assert_eq!(Future::poll(Pin::new(&mut delay), &mut cx), Poll::Pending);
+		assert!(!*flag.0.lock().unwrap(), "delay shouldn't wake before it's even due");
+
+		// Overshoot the deadline by a wide margin, as a test stepping through a batch would:
+		// this must still wake the delay, not just an exact jump onto `100`.
+		clock.advance(offchain::Duration::from_millis(150));
+
+		assert!(*flag.0.lock().unwrap(), "advancing past the deadline should wake the delay");
+		assert_eq!(Future::poll(Pin::new(&mut delay), &mut cx), Poll::Ready(()));
+	}
+}